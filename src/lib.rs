@@ -3,15 +3,28 @@ use proxy_wasm::{
     traits::{Context, HttpContext, RootContext},
     types::{Action, LogLevel},
 };
-use serde::Deserialize;
-use serde_json::{Map, Value};
-use std::{cell::RefCell, collections::HashMap, error::Error, time::Duration};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    error::Error,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 const POWERED_BY: &str = "header-augmenting-filter";
 const CACHE_KEY: &str = "cache";
+const CACHE_INDEX_KEY: &str = "cache_index";
+const VARIANCE_SEPARATOR: char = '\u{1}';
 const INITIALISATION_TICK: Duration = Duration::from_secs(3);
+const REFRESHING_SUFFIX: &str = ":refreshing";
+// How long a dispatched HTTP call to the header providing service is given
+// to complete, both for the call itself and for how long a background
+// refresh's single-flight lock is honored before it's considered abandoned
+// (e.g. the HttpHandler that held it was torn down without ever seeing
+// on_http_call_response) and reclaimable by the next caller.
+const DISPATCH_TIMEOUT: Duration = Duration::from_secs(5);
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 #[serde(default)]
 struct FilterConfig {
     /// The Envoy cluster name housing a HTTP service that will provide headers
@@ -24,9 +37,132 @@ struct FilterConfig {
     /// The authority to set when calling the HTTP service providing headers.
     header_providing_service_authority: String,
 
-    /// The length of time to keep headers cached.
+    /// The length of time to keep headers cached, used when the header
+    /// providing service does not send back a usable `Cache-Control` header.
     #[serde(with = "serde_humanize_rs")]
     header_cache_expiry: Duration,
+
+    /// The maximum length of time to keep headers cached, regardless of what
+    /// `max-age` the header providing service asks for. Guards against a
+    /// misbehaving sidecar pinning a multi-day TTL.
+    #[serde(with = "serde_humanize_rs")]
+    max_cache_expiry: Duration,
+
+    /// Request headers whose values partition the header cache, e.g.
+    /// `["host", "x-tenant-id"]` to cache a distinct set of augmented
+    /// headers per authority/tenant pair. Empty means a single, global
+    /// cache entry shared by every request.
+    cache_key_headers: Vec<String>,
+
+    /// Inbound request headers to forward to the header providing service on
+    /// a cache miss, so it can compute augmentations derived from the
+    /// request (claims, rate-limit tokens, routing hints) rather than just
+    /// returning a static payload. Empty keeps the filter request-agnostic.
+    forward_headers: Vec<String>,
+
+    /// Whether to also forward the request body on a blocking cache miss.
+    /// Requires buffering the body before calling the header providing
+    /// service, so only enable this when the sidecar actually needs it. Not
+    /// honored by a stale-while-revalidate background refresh, which never
+    /// pauses or buffers the body of the (already-answered) request that
+    /// triggered it.
+    forward_request_body: bool,
+
+    /// Whether `RootHandler::on_tick` should keep prefetching and refreshing
+    /// cache partitions on a timer. This is only useful for
+    /// context-independent deployments, i.e. ones that don't rely on
+    /// `forward_headers`/`forward_request_body`; disable it when every
+    /// partition is populated on demand from the request path instead.
+    warm_cache_prefetch: bool,
+
+    /// How to apply a cached header onto the request or response when the
+    /// header providing service's response doesn't specify a mode of its own.
+    apply_mode: ApplyMode,
+
+    /// Request headers to strip before augmentation, so a client can't
+    /// spoof a header (e.g. `X-User-Id`) the sidecar is trusted to set.
+    remove_headers: Vec<String>,
+
+    /// Response headers to strip before augmentation, mirroring
+    /// `remove_headers` for the downstream response path.
+    remove_response_headers: Vec<String>,
+
+    /// How long a cache entry may be served past its `fresh_until` while a
+    /// single coalesced background refresh is in flight. Once an entry is
+    /// stale by more than this, the filter stops serving it and falls back
+    /// to the blocking/initialisation path instead.
+    #[serde(with = "serde_humanize_rs")]
+    stale_max_age: Duration,
+}
+
+/// How a cached header value gets applied onto the request or response.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+enum ApplyMode {
+    /// Unconditionally replace any existing value (the historical behavior).
+    #[default]
+    Overwrite,
+    /// Only set the header when the client didn't already send one.
+    InsertIfAbsent,
+    /// Add another value, accumulating a multi-valued header.
+    Append,
+}
+
+/// Which leg(s) of the exchange a cached header should be applied to.
+/// Defaults to `Request` so existing sidecar payloads keep augmenting only
+/// the request, as before this was configurable.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+enum Direction {
+    #[default]
+    Request,
+    Response,
+    Both,
+}
+
+/// A header value returned by the header providing service, either the
+/// plain string form or the richer object form carrying a per-header
+/// `apply_mode`/`direction` override.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum HeaderEntry {
+    Plain(String),
+    Detailed {
+        value: String,
+        mode: Option<ApplyMode>,
+        direction: Option<Direction>,
+    },
+}
+
+impl HeaderEntry {
+    fn value(&self) -> &str {
+        match self {
+            HeaderEntry::Plain(value) => value,
+            HeaderEntry::Detailed { value, .. } => value,
+        }
+    }
+
+    fn mode(&self) -> Option<ApplyMode> {
+        match self {
+            HeaderEntry::Plain(_) => None,
+            HeaderEntry::Detailed { mode, .. } => *mode,
+        }
+    }
+
+    fn direction(&self) -> Direction {
+        match self {
+            HeaderEntry::Plain(_) => Direction::default(),
+            HeaderEntry::Detailed { direction, .. } => direction.unwrap_or_default(),
+        }
+    }
+
+    fn applies_to_request(&self) -> bool {
+        matches!(self.direction(), Direction::Request | Direction::Both)
+    }
+
+    fn applies_to_response(&self) -> bool {
+        matches!(self.direction(), Direction::Response | Direction::Both)
+    }
 }
 
 impl Default for FilterConfig {
@@ -36,12 +172,99 @@ impl Default for FilterConfig {
             header_providing_service_path: "/headers".to_owned(),
             header_providing_service_authority: "sidecar".to_owned(),
             header_cache_expiry: Duration::from_secs(360),
+            max_cache_expiry: Duration::from_secs(3600),
+            cache_key_headers: vec![],
+            forward_headers: vec![],
+            forward_request_body: false,
+            warm_cache_prefetch: true,
+            apply_mode: ApplyMode::Overwrite,
+            remove_headers: vec![],
+            remove_response_headers: vec![],
+            stale_max_age: Duration::from_secs(60),
         }
     }
 }
 
 thread_local! {
-    static CONFIGS: RefCell<HashMap<u32, FilterConfig>> = RefCell::new(HashMap::new())
+    static CONFIGS: RefCell<HashMap<u32, FilterConfig>> = RefCell::new(HashMap::new());
+    // Maps an in-flight dispatch_http_call token to the variance key it's
+    // refreshing, so on_http_call_response knows which cache partition to
+    // populate.
+    static PENDING_CALLS: RefCell<HashMap<u32, String>> = RefCell::new(HashMap::new());
+}
+
+/// Build the stable variance string for a set of cache-key header values,
+/// and the shared-data key it maps to.
+fn variance_of(components: &[String]) -> String {
+    components.join(&VARIANCE_SEPARATOR.to_string())
+}
+
+fn cache_key_for(variance: &str) -> String {
+    format!("{}:{}", CACHE_KEY, variance)
+}
+
+fn refresh_lock_key_for(cache_key: &str) -> String {
+    format!("{}{}", cache_key, REFRESHING_SUFFIX)
+}
+
+fn index_from_shared_data(data: Option<Vec<u8>>) -> HashMap<String, Vec<String>> {
+    data.and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// A cached header payload plus enough bookkeeping to serve it
+/// stale-while-revalidate: the body stays usable for `fresh_until - fetched_at`,
+/// then for up to `stale_max_age` more while a refresh is coalesced in the
+/// background.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CacheEntry {
+    body: String,
+    fetched_at: u64,
+    fresh_until: u64,
+}
+
+impl CacheEntry {
+    fn new(body: Vec<u8>, ttl: Duration, now: SystemTime) -> Self {
+        let fetched_at = unix_secs(now);
+
+        CacheEntry {
+            body: String::from_utf8_lossy(&body).into_owned(),
+            fetched_at,
+            fresh_until: fetched_at + ttl.as_secs(),
+        }
+    }
+
+    fn is_fresh(&self, now: SystemTime) -> bool {
+        unix_secs(now) <= self.fresh_until
+    }
+
+    fn is_usable(&self, stale_max_age: Duration, now: SystemTime) -> bool {
+        unix_secs(now) <= self.fresh_until + stale_max_age.as_secs()
+    }
+}
+
+/// Work out how long a header providing service response should be cached
+/// for, based on its `Cache-Control` header (falling back to the configured
+/// expiry), capped at `max_cache_expiry`. `None` means the response must not
+/// be cached at all.
+fn ttl_for(response_headers: &[(String, String)], config: &FilterConfig) -> Option<Duration> {
+    let directive = response_headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("cache-control"))
+        .and_then(|(_, value)| parse_cache_control(value));
+
+    match directive {
+        Some(CacheControl::NoStore) => None,
+        Some(CacheControl::MaxAge(max_age)) => Some(max_age.min(config.max_cache_expiry)),
+        Some(CacheControl::NoCache) => Some(INITIALISATION_TICK),
+        None => Some(config.header_cache_expiry),
+    }
 }
 
 #[no_mangle]
@@ -63,8 +286,13 @@ pub fn _start() {
     });
 
     // called during http filter chain
-    proxy_wasm::set_http_context(|_context_id, _root_context_id| -> Box<dyn HttpContext> {
-        Box::new(HttpHandler {})
+    proxy_wasm::set_http_context(|_context_id, root_context_id| -> Box<dyn HttpContext> {
+        Box::new(HttpHandler {
+            root_context_id,
+            awaiting_body: false,
+            pending_dispatch: None,
+            resolved_headers: None,
+        })
     })
 }
 
@@ -99,49 +327,80 @@ impl RootContext for RootHandler {
             }
         }
 
-        // Configure an initialisation tick and the cache.
+        // Configure an initialisation tick and the cache. The index starts
+        // out tracking the global (no variance) partition so the cache
+        // keeps working out of the box when `cache_key_headers` is unset.
         self.set_tick_period(INITIALISATION_TICK);
-        self.set_shared_data(CACHE_KEY, None, None).is_ok()
+
+        let mut index: HashMap<String, Vec<String>> = HashMap::new();
+        index.insert(String::new(), vec![]);
+
+        self.set_shared_data(
+            CACHE_INDEX_KEY,
+            Some(&serde_json::to_vec(&index).unwrap()),
+            None,
+        )
+        .is_ok()
     }
 
     // on_tick do the following actions every `tick_period`
     fn on_tick(&mut self) {
-        // Log the action that is about to be taken.
-        match self.get_shared_data(CACHE_KEY) {
-            (None, _) => debug!("initialising cached headers"),
-            (Some(_), _) => debug!("refreshing cached headers"),
-        }
+        let (index, _) = self.get_shared_data(CACHE_INDEX_KEY);
+        let index = index_from_shared_data(index);
 
-        // this gets called every `tick_period` and then dispatches an http call
+        // this gets called every `tick_period` and then dispatches one http
+        // call per live cache partition
         CONFIGS.with(|configs| {
             configs.borrow().get(&self.context_id).map(|config| {
+                if !config.warm_cache_prefetch {
+                    // This deployment populates every partition on demand
+                    // from the request path instead; nothing to prefetch.
+                    return;
+                }
+
                 // We could be in the initialisation tick here so update our
                 // tick period to the configured expiry before doing anything.
                 // This will be reset to an initialisation tick upon failures.
                 self.set_tick_period(config.header_cache_expiry);
 
-                // Dispatch an async HTTP call to the configured cluster.
-                // remember this is an async HTTP call
-                // this is a trait of Context, RootContext extends it
-                self.dispatch_http_call(
-                    // this is what is getting the Authorization
-                    &config.header_providing_service_cluster,
-                    vec![
+                for (variance, components) in &index {
+                    debug!("refreshing cache partition {:?}", variance);
+
+                    let mut headers = vec![
                         (":method", "GET"),
                         (":path", &config.header_providing_service_path),
                         (":authority", &config.header_providing_service_authority),
-                    ],
-                    None,
-                    vec![],
-                    Duration::from_secs(5),
-                )
-                .map_err(|e| {
-                    // Something went wrong instantly. Reset to an
-                    // initialisation tick for a quick retry.
-                    self.set_tick_period(INITIALISATION_TICK);
-
-                    warn!("failed calling header providing service: {:?}", e)
-                })
+                    ];
+
+                    for (name, value) in config.cache_key_headers.iter().zip(components) {
+                        headers.push((name.as_str(), value.as_str()));
+                    }
+
+                    // Dispatch an async HTTP call to the configured cluster.
+                    // remember this is an async HTTP call
+                    // this is a trait of Context, RootContext extends it
+                    match self.dispatch_http_call(
+                        // this is what is getting the Authorization
+                        &config.header_providing_service_cluster,
+                        headers,
+                        None,
+                        vec![],
+                        DISPATCH_TIMEOUT,
+                    ) {
+                        Ok(token_id) => {
+                            PENDING_CALLS.with(|pending| {
+                                pending.borrow_mut().insert(token_id, variance.clone());
+                            });
+                        }
+                        Err(e) => {
+                            // Something went wrong instantly. Reset to an
+                            // initialisation tick for a quick retry.
+                            self.set_tick_period(INITIALISATION_TICK);
+
+                            warn!("failed calling header providing service: {:?}", e)
+                        }
+                    }
+                }
             })
         });
     }
@@ -150,11 +409,42 @@ impl RootContext for RootHandler {
 impl Context for RootHandler {
     fn on_http_call_response(
         &mut self,
-        _token_id: u32,
+        token_id: u32,
         _num_headers: usize,
         body_size: usize,
         _num_trailers: usize,
     ) {
+        let variance = PENDING_CALLS.with(|pending| pending.borrow_mut().remove(&token_id));
+        let variance = match variance {
+            Some(variance) => variance,
+            None => {
+                warn!("received response for unknown call token {}", token_id);
+
+                return;
+            }
+        };
+        let cache_key = cache_key_for(&variance);
+
+        let config = CONFIGS.with(|configs| configs.borrow().get(&self.context_id).cloned());
+        let config = match config {
+            Some(config) => config,
+            None => return,
+        };
+
+        let response_headers = self.get_http_call_response_headers();
+        let ttl = ttl_for(&response_headers, &config);
+
+        let ttl = match ttl {
+            Some(ttl) => ttl,
+            None => {
+                debug!("header providing service sent no-store, skipping cache");
+
+                self.set_tick_period(INITIALISATION_TICK);
+
+                return;
+            }
+        };
+
         // Gather the response body of previously dispatched async HTTP call.
         let body = match self.get_http_call_response_body(0, body_size) {
             Some(body) => body,
@@ -165,47 +455,97 @@ impl Context for RootHandler {
             }
         };
 
-        // Store the body in the shared cache.
-        match self.set_shared_data(CACHE_KEY, Some(&body), None) {
-            Ok(()) => debug!(
-                "refreshed header cache with: {}",
-                String::from_utf8(body.clone()).unwrap()
-            ),
+        let entry = CacheEntry::new(body, ttl, self.get_current_time());
+
+        // Store the entry in the shared cache, keyed by variance.
+        match self.set_shared_data(&cache_key, Some(&serde_json::to_vec(&entry).unwrap()), None) {
+            Ok(()) => debug!("refreshed header cache with: {}", entry.body),
 
             Err(e) => {
                 warn!("failed storing header cache: {:?}", e);
 
                 // Reset to an initialisation tick for a quick retry.
-                self.set_tick_period(INITIALISATION_TICK)
+                self.set_tick_period(INITIALISATION_TICK);
+
+                return;
             }
         }
+
+        self.set_tick_period(ttl);
     }
 }
 
-struct HttpHandler {}
+#[derive(Debug, PartialEq)]
+enum CacheControl {
+    MaxAge(Duration),
+    NoStore,
+    NoCache,
+}
 
-impl HttpContext for HttpHandler {
-    fn on_http_request_headers(&mut self, _num_headers: usize) -> Action {
-        match self.get_shared_data(CACHE_KEY) {
-            (Some(cache), _) => {
-                debug!(
-                    "using existing header cache: {}",
-                    String::from_utf8(cache.clone()).unwrap()
-                );
+/// Parse a `Cache-Control` header value, recognising the `max-age`,
+/// `no-store` and `no-cache` directives. Unknown or malformed directives are
+/// ignored; `None` is returned if nothing usable was found.
+fn parse_cache_control(value: &str) -> Option<CacheControl> {
+    let mut max_age = None;
 
-                match self.parse_headers(&cache) {
-                    Ok(headers) => {
-                        for (name, value) in headers {
-                            self.set_http_request_header(&name, value.as_str())
-                        }
-                    }
-                    Err(e) => warn!("no usable headers cached: {:?}", e),
-                }
+    for token in value.split(',').map(str::trim) {
+        if token.eq_ignore_ascii_case("no-store") {
+            return Some(CacheControl::NoStore);
+        }
 
-                Action::Continue
+        if token.eq_ignore_ascii_case("no-cache") {
+            return Some(CacheControl::NoCache);
+        }
+
+        if let Some(seconds) = token
+            .strip_prefix("max-age=")
+            .or_else(|| token.strip_prefix("max-age ="))
+        {
+            if let Ok(seconds) = seconds.trim().parse::<u64>() {
+                max_age = Some(Duration::from_secs(seconds));
             }
-            (None, _) => {
-                warn!("filter not initialised");
+        }
+    }
+
+    max_age.map(CacheControl::MaxAge)
+}
+
+/// Everything needed to dispatch a deferred cache-miss call once we've
+/// finished deciding (and possibly buffering the body for) a request.
+struct PendingDispatch {
+    config: FilterConfig,
+    components: Vec<String>,
+    cache_key: String,
+    // Whether this call is a stale-while-revalidate refresh running behind
+    // an already-answered request, as opposed to a blocking cache-miss call
+    // that the current request is paused on.
+    background: bool,
+}
+
+struct HttpHandler {
+    root_context_id: u32,
+    // Set while we've paused the request waiting for its body to finish
+    // arriving, so on_http_request_body knows to keep buffering.
+    awaiting_body: bool,
+    // Set while a call to the header providing service (a cache-miss call
+    // or a background refresh) is in flight, so on_http_call_response knows
+    // where to store the result, and earlier while a cache-miss call is
+    // deferred until the request body has been buffered.
+    pending_dispatch: Option<PendingDispatch>,
+    // The response-directed headers resolved for this request (from a cache
+    // hit or a completed cache-miss dispatch), kept around so
+    // on_http_response_headers can apply them once the upstream response
+    // arrives, without re-parsing the cached payload.
+    resolved_headers: Option<(FilterConfig, Vec<(String, HeaderEntry)>)>,
+}
+
+impl HttpContext for HttpHandler {
+    fn on_http_request_headers(&mut self, _num_headers: usize) -> Action {
+        let config = CONFIGS.with(|configs| configs.borrow().get(&self.root_context_id).cloned());
+        let config = match config {
+            Some(config) => config,
+            None => {
+                warn!("filter not configured");
 
                 self.send_http_response(
                     500,
@@ -213,27 +553,570 @@ impl HttpContext for HttpHandler {
                     Some(b"Filter not initialised"),
                 );
 
-                Action::Pause
+                return Action::Pause;
             }
+        };
+
+        let components: Vec<String> = config
+            .cache_key_headers
+            .iter()
+            .map(|name| self.get_http_request_header(name).unwrap_or_default())
+            .collect();
+        let variance = variance_of(&components);
+        let cache_key = cache_key_for(&variance);
+        let now = self.get_current_time();
+
+        let entry = match self.get_shared_data(&cache_key) {
+            (Some(bytes), _) => serde_json::from_slice::<CacheEntry>(&bytes).ok(),
+            (None, _) => None,
+        };
+
+        match entry {
+            Some(entry) if entry.is_fresh(now) => {
+                debug!("using fresh header cache for {:?}", variance);
+
+                let response_headers =
+                    self.apply_cached_request_headers(&config, entry.body.as_bytes());
+                self.resolved_headers = Some((config, response_headers));
+
+                Action::Continue
+            }
+            Some(entry) if entry.is_usable(config.stale_max_age, now) => {
+                // Serve the stale entry immediately and kick off a coalesced
+                // background refresh rather than blocking this request on it.
+                debug!("serving stale header cache for {:?}", variance);
+
+                let response_headers =
+                    self.apply_cached_request_headers(&config, entry.body.as_bytes());
+                self.trigger_background_refresh(&config, &components, &cache_key);
+                self.resolved_headers = Some((config, response_headers));
+
+                Action::Continue
+            }
+            _ => self.handle_cache_miss(config, components, variance, cache_key),
+        }
+    }
+
+    fn on_http_request_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        // Only a blocking cache-miss (handle_cache_miss) ever sets
+        // awaiting_body; background refreshes dispatch immediately without
+        // forwarding the body, so they never intercept a live request's body
+        // frames here.
+        if !self.awaiting_body {
+            return Action::Continue;
+        }
+
+        if !end_of_stream {
+            // Keep buffering until we've seen the whole body.
+            return Action::Pause;
         }
+
+        self.awaiting_body = false;
+
+        let PendingDispatch {
+            config,
+            components,
+            cache_key,
+            background,
+        } = match self.pending_dispatch.take() {
+            Some(pending) => pending,
+            None => return Action::Continue,
+        };
+
+        let body = self.get_http_request_body(0, body_size);
+
+        self.dispatch_to_header_service(
+            &config,
+            &components,
+            &cache_key,
+            body.as_deref(),
+            background,
+        );
+
+        Action::Pause
+    }
+
+    fn on_http_response_headers(&mut self, _num_headers: usize) -> Action {
+        if let Some((config, headers)) = self.resolved_headers.take() {
+            self.apply_cached_response_headers(&config, &headers);
+        }
+
+        Action::Continue
     }
 }
 
-impl Context for HttpHandler {}
+impl Context for HttpHandler {
+    fn on_http_call_response(
+        &mut self,
+        _token_id: u32,
+        _num_headers: usize,
+        body_size: usize,
+        _num_trailers: usize,
+    ) {
+        let pending = match self.pending_dispatch.take() {
+            Some(pending) => pending,
+            None => {
+                warn!("received unexpected header providing service response");
+
+                return;
+            }
+        };
+
+        let body = match self.get_http_call_response_body(0, body_size) {
+            Some(body) => body,
+            None => {
+                warn!("header providing service returned empty body");
+
+                if pending.background {
+                    self.clear_refresh_lock(&pending.cache_key);
+                } else {
+                    self.send_http_response(
+                        500,
+                        vec![("Powered-By", POWERED_BY)],
+                        Some(b"Filter not initialised"),
+                    );
+                }
+
+                return;
+            }
+        };
+
+        if let Some(ttl) = ttl_for(&self.get_http_call_response_headers(), &pending.config) {
+            let entry = CacheEntry::new(body.clone(), ttl, self.get_current_time());
+
+            if let Err(e) = self.set_shared_data(
+                &pending.cache_key,
+                Some(&serde_json::to_vec(&entry).unwrap()),
+                None,
+            ) {
+                warn!("failed storing header cache: {:?}", e);
+            }
+        }
+
+        if pending.background {
+            // The original request was already answered with stale data;
+            // just clear the single-flight lock so the next refresh can run.
+            self.clear_refresh_lock(&pending.cache_key);
+
+            return;
+        }
+
+        let response_headers = self.apply_cached_request_headers(&pending.config, &body);
+        self.resolved_headers = Some((pending.config, response_headers));
+        self.resume_http_request();
+    }
+}
 
 impl HttpHandler {
-    fn parse_headers(&self, res: &[u8]) -> Result<Map<String, Value>, Box<dyn Error>> {
-        Ok(serde_json::from_slice::<Value>(&res)?
-            .as_object()
-            .unwrap()
-            .clone())
+    /// Parse the cached payload into header entries, skipping (and warning
+    /// about) any individual entry with an unexpected shape rather than
+    /// rejecting the whole payload over one bad entry.
+    fn parse_headers(&self, res: &[u8]) -> Result<HashMap<String, HeaderEntry>, Box<dyn Error>> {
+        let raw: HashMap<String, serde_json::Value> = serde_json::from_slice(res)?;
+
+        Ok(raw
+            .into_iter()
+            .filter_map(|(name, value)| match serde_json::from_value(value) {
+                Ok(entry) => Some((name, entry)),
+                Err(e) => {
+                    warn!("skipping unusable cached header {:?}: {:?}", name, e);
+
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Apply the request-directed headers from a cached payload, returning
+    /// whichever entries are also (or only) directed at the response so the
+    /// caller can stash them for `apply_cached_response_headers` without
+    /// re-parsing the payload later.
+    fn apply_cached_request_headers(
+        &mut self,
+        config: &FilterConfig,
+        cache: &[u8],
+    ) -> Vec<(String, HeaderEntry)> {
+        for name in &config.remove_headers {
+            self.remove_http_request_header(name);
+        }
+
+        let headers = match self.parse_headers(cache) {
+            Ok(headers) => headers,
+            Err(e) => {
+                warn!("no usable headers cached: {:?}", e);
+
+                return vec![];
+            }
+        };
+
+        let mut response_headers = vec![];
+
+        for (name, entry) in headers {
+            if entry.applies_to_request() {
+                let mode = entry.mode().unwrap_or(config.apply_mode);
+
+                self.apply_header(&name, entry.value(), mode);
+            }
+
+            if entry.applies_to_response() {
+                response_headers.push((name, entry));
+            }
+        }
+
+        response_headers
+    }
+
+    /// Apply the headers directed at the response, mirroring
+    /// `apply_cached_request_headers` for the downstream leg of the
+    /// exchange. Called from `on_http_response_headers` once the upstream
+    /// response arrives, against the entries already picked out by
+    /// `apply_cached_request_headers` for this request.
+    fn apply_cached_response_headers(
+        &mut self,
+        config: &FilterConfig,
+        headers: &[(String, HeaderEntry)],
+    ) {
+        for name in &config.remove_response_headers {
+            self.remove_http_response_header(name);
+        }
+
+        for (name, entry) in headers {
+            let mode = entry.mode().unwrap_or(config.apply_mode);
+
+            self.apply_response_header(name, entry.value(), mode);
+        }
+    }
+
+    fn apply_header(&mut self, name: &str, value: &str, mode: ApplyMode) {
+        match mode {
+            ApplyMode::Overwrite => self.set_http_request_header(name, Some(value)),
+            ApplyMode::InsertIfAbsent => {
+                if self.get_http_request_header(name).is_none() {
+                    self.set_http_request_header(name, Some(value));
+                }
+            }
+            ApplyMode::Append => self.add_http_request_header(name, value),
+        }
+    }
+
+    fn apply_response_header(&mut self, name: &str, value: &str, mode: ApplyMode) {
+        match mode {
+            ApplyMode::Overwrite => self.set_http_response_header(name, Some(value)),
+            ApplyMode::InsertIfAbsent => {
+                if self.get_http_response_header(name).is_none() {
+                    self.set_http_response_header(name, Some(value));
+                }
+            }
+            ApplyMode::Append => self.add_http_response_header(name, value),
+        }
+    }
+
+    /// Register a not-yet-seen variance key (and the header values that
+    /// produced it) in the shared cache index, so the next root-context tick
+    /// picks it up and populates its partition.
+    fn register_cache_partition(&self, variance: &str, components: &[String]) {
+        let (data, cas) = self.get_shared_data(CACHE_INDEX_KEY);
+        let mut index = index_from_shared_data(data);
+
+        if index.contains_key(variance) {
+            return;
+        }
+
+        index.insert(variance.to_owned(), components.to_owned());
+
+        if let Err(e) = self.set_shared_data(
+            CACHE_INDEX_KEY,
+            Some(&serde_json::to_vec(&index).unwrap()),
+            cas,
+        ) {
+            warn!("failed registering cache partition {:?}: {:?}", variance, e);
+        }
+    }
+
+    /// There's no usable (fresh or stale-but-within-bound) entry for this
+    /// partition: fall back to the blocking/initialisation behavior, either
+    /// waiting on the tick-based warm-cache prefetch or dispatching a
+    /// request-driven call ourselves.
+    fn handle_cache_miss(
+        &mut self,
+        config: FilterConfig,
+        components: Vec<String>,
+        variance: String,
+        cache_key: String,
+    ) -> Action {
+        if config.forward_headers.is_empty() {
+            // No request context is needed by the sidecar, so fall back to
+            // the tick-based warm-cache prefetch: register this partition
+            // and report not-initialised until a tick fills it.
+            warn!("filter not initialised for cache partition {:?}", variance);
+
+            self.register_cache_partition(&variance, &components);
+
+            self.send_http_response(
+                500,
+                vec![("Powered-By", POWERED_BY)],
+                Some(b"Filter not initialised"),
+            );
+
+            Action::Pause
+        } else if config.forward_request_body {
+            // Defer the call until the body has finished arriving.
+            self.awaiting_body = true;
+            self.pending_dispatch = Some(PendingDispatch {
+                config,
+                components,
+                cache_key,
+                background: false,
+            });
+
+            Action::Pause
+        } else {
+            self.dispatch_to_header_service(&config, &components, &cache_key, None, false);
+
+            Action::Pause
+        }
+    }
+
+    /// Kick off a single coalesced refresh for a stale partition. Uses the
+    /// shared-data CAS token as a lock so only the first caller to observe
+    /// the partition as stale actually dispatches a call; everyone else just
+    /// keeps serving the stale entry until that refresh completes.
+    ///
+    /// The lock stores the unix timestamp it was acquired at rather than a
+    /// bare flag, so a lock whose holder never cleared it (its HttpHandler
+    /// was torn down before on_http_call_response ran, for instance) doesn't
+    /// wedge the partition forever: once it's older than `DISPATCH_TIMEOUT`,
+    /// the dispatch it was guarding must have already failed or been
+    /// abandoned, so the next caller reclaims it instead.
+    fn trigger_background_refresh(
+        &mut self,
+        config: &FilterConfig,
+        components: &[String],
+        cache_key: &str,
+    ) {
+        let lock_key = refresh_lock_key_for(cache_key);
+        let (locked_at, cas) = self.get_shared_data(&lock_key);
+        let now = unix_secs(self.get_current_time());
+
+        let locked_at = locked_at.and_then(|bytes| {
+            std::str::from_utf8(&bytes)
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+        });
+
+        if let Some(locked_at) = locked_at {
+            if now.saturating_sub(locked_at) < DISPATCH_TIMEOUT.as_secs() {
+                debug!("refresh already in flight for {:?}", cache_key);
+
+                return;
+            }
+
+            debug!("reclaiming abandoned refresh lock for {:?}", cache_key);
+        }
+
+        if self
+            .set_shared_data(&lock_key, Some(now.to_string().as_bytes()), cas)
+            .is_err()
+        {
+            debug!("lost the race to refresh {:?}", cache_key);
+
+            return;
+        }
+
+        // Dispatch immediately, without forwarding the request body, even if
+        // `forward_request_body` is set. This request already got
+        // `Action::Continue` and must not have its body intercepted by the
+        // awaiting_body/pending_dispatch machinery that blocking cache-miss
+        // requests use to pause and buffer: doing so would hold up this
+        // live request's body, and would delay the dispatch (and thus the
+        // start of the lock's timeout clock) until end_of_stream, which may
+        // never arrive promptly for a long-lived/streaming body, starving
+        // every other stale hit on this partition.
+        self.dispatch_to_header_service(config, components, cache_key, None, true);
+    }
+
+    fn clear_refresh_lock(&mut self, cache_key: &str) {
+        let _ = self.set_shared_data(&refresh_lock_key_for(cache_key), None, None);
+    }
+
+    /// Dispatch a cache-miss (or stale-while-revalidate refresh) call to the
+    /// header providing service, carrying the configured `forward_headers`
+    /// (and optionally the request body) so it can compute a response
+    /// derived from this specific request.
+    fn dispatch_to_header_service(
+        &mut self,
+        config: &FilterConfig,
+        components: &[String],
+        cache_key: &str,
+        body: Option<&[u8]>,
+        background: bool,
+    ) {
+        let mut headers = vec![
+            (":method", "POST"),
+            (":path", config.header_providing_service_path.as_str()),
+            (
+                ":authority",
+                config.header_providing_service_authority.as_str(),
+            ),
+        ];
+
+        let forwarded: Vec<(String, String)> = config
+            .forward_headers
+            .iter()
+            .filter_map(|name| {
+                self.get_http_request_header(name)
+                    .map(|v| (name.clone(), v))
+            })
+            .collect();
+
+        for (name, value) in &forwarded {
+            headers.push((name.as_str(), value.as_str()));
+        }
+
+        for (name, value) in config.cache_key_headers.iter().zip(components) {
+            headers.push((name.as_str(), value.as_str()));
+        }
+
+        match self.dispatch_http_call(
+            &config.header_providing_service_cluster,
+            headers,
+            body,
+            vec![],
+            DISPATCH_TIMEOUT,
+        ) {
+            Ok(_token_id) => {
+                self.pending_dispatch = Some(PendingDispatch {
+                    config: config.clone(),
+                    components: components.to_owned(),
+                    cache_key: cache_key.to_owned(),
+                    background,
+                });
+            }
+            Err(e) => {
+                warn!("failed calling header providing service: {:?}", e);
+
+                if background {
+                    self.clear_refresh_lock(cache_key);
+                } else {
+                    self.send_http_response(
+                        500,
+                        vec![("Powered-By", POWERED_BY)],
+                        Some(b"Filter not initialised"),
+                    );
+                }
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn todo() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn parses_max_age() {
+        assert_eq!(
+            parse_cache_control("max-age=120"),
+            Some(CacheControl::MaxAge(Duration::from_secs(120)))
+        );
+    }
+
+    #[test]
+    fn parses_no_store_and_no_cache() {
+        assert_eq!(
+            parse_cache_control("private, no-store"),
+            Some(CacheControl::NoStore)
+        );
+        assert_eq!(parse_cache_control("no-cache"), Some(CacheControl::NoCache));
+    }
+
+    #[test]
+    fn ignores_unknown_directives() {
+        assert_eq!(parse_cache_control("private, immutable"), None);
+    }
+
+    #[test]
+    fn cache_entry_tracks_freshness_and_staleness_bounds() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000);
+        let entry = CacheEntry::new(b"abc".to_vec(), Duration::from_secs(60), now);
+
+        assert!(entry.is_fresh(now));
+        assert!(entry.is_fresh(now + Duration::from_secs(60)));
+        assert!(!entry.is_fresh(now + Duration::from_secs(61)));
+
+        assert!(entry.is_usable(Duration::from_secs(30), now + Duration::from_secs(90)));
+        assert!(!entry.is_usable(Duration::from_secs(30), now + Duration::from_secs(91)));
+    }
+
+    #[test]
+    fn header_entry_accepts_plain_and_detailed_forms() {
+        let headers: HashMap<String, HeaderEntry> = serde_json::from_str(
+            r#"{"x-plain": "abc", "x-detailed": {"value": "xyz", "mode": "append"}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(headers["x-plain"].value(), "abc");
+        assert_eq!(headers["x-plain"].mode(), None);
+
+        assert_eq!(headers["x-detailed"].value(), "xyz");
+        assert_eq!(headers["x-detailed"].mode(), Some(ApplyMode::Append));
+    }
+
+    #[test]
+    fn header_entry_direction_defaults_to_request_only() {
+        let headers: HashMap<String, HeaderEntry> = serde_json::from_str(
+            r#"{
+                "x-plain": "abc",
+                "x-response-only": {"value": "xyz", "direction": "response"},
+                "x-both": {"value": "both", "direction": "both"}
+            }"#,
+        )
+        .unwrap();
+
+        assert!(headers["x-plain"].applies_to_request());
+        assert!(!headers["x-plain"].applies_to_response());
+
+        assert!(!headers["x-response-only"].applies_to_request());
+        assert!(headers["x-response-only"].applies_to_response());
+
+        assert!(headers["x-both"].applies_to_request());
+        assert!(headers["x-both"].applies_to_response());
+    }
+
+    #[test]
+    fn background_refresh_never_pauses_live_request_body() {
+        // trigger_background_refresh dispatches immediately (without
+        // forwarding the body) and never sets awaiting_body, even when
+        // forward_request_body is configured. Simulate the state it leaves
+        // behind on the HttpHandler of a request it refreshed in the
+        // background, and confirm on_http_request_body lets every frame of
+        // that already-continued request through untouched rather than
+        // buffering it for the refresh call.
+        let mut config = FilterConfig::default();
+        config.forward_request_body = true;
+
+        let mut handler = HttpHandler {
+            root_context_id: 0,
+            awaiting_body: false,
+            pending_dispatch: Some(PendingDispatch {
+                config,
+                components: vec![],
+                cache_key: "cache:".to_owned(),
+                background: true,
+            }),
+            resolved_headers: None,
+        };
+
+        assert_eq!(handler.on_http_request_body(0, false), Action::Continue);
+        assert_eq!(handler.on_http_request_body(0, true), Action::Continue);
+
+        // The dispatch was already made up front by trigger_background_refresh,
+        // not deferred to this callback, so its state is left untouched.
+        assert!(handler.pending_dispatch.is_some());
+    }
 }